@@ -0,0 +1,42 @@
+use resvg::usvg::fontdb;
+use rust_embed::RustEmbed;
+
+/// Fonts bundled into the binary so serif glyphs render the same on a
+/// stripped container image as on a developer's machine, instead of relying
+/// on whichever system fonts happen to be installed.
+///
+/// TODO: no CJK-capable face is bundled yet, so CJK `<text>` output still
+/// renders as tofu on a container with no CJK system fonts installed — see
+/// `assets/fonts/README.md` for what's needed to close that gap.
+#[derive(RustEmbed)]
+#[folder = "assets/fonts/"]
+struct EmbeddedFonts;
+
+/// Extensions `EmbeddedFonts` actually loads as fonts; everything else in
+/// the folder (docs, etc.) is skipped rather than handed to `fontdb` as
+/// font data.
+const FONT_EXTENSIONS: &[&str] = &["ttf", "ttc", "otf"];
+
+/// Name of the bundled serif face to set as the fontdb's default serif family
+pub const SERIF_FAMILY: &str = "DejaVu Serif";
+
+/// Build a fontdb pre-loaded with the embedded fonts, falling back to system
+/// fonts for anything not bundled
+pub fn build_fontdb() -> fontdb::Database {
+    let mut fdb = fontdb::Database::new();
+    for path in EmbeddedFonts::iter() {
+        let is_font = std::path::Path::new(path.as_ref())
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| FONT_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()));
+        if !is_font {
+            continue;
+        }
+        if let Some(font) = EmbeddedFonts::get(&path) {
+            fdb.load_font_data(font.data.into_owned());
+        }
+    }
+    fdb.load_system_fonts();
+    fdb.set_serif_family(SERIF_FAMILY);
+    fdb
+}
@@ -1,4 +1,8 @@
-use std::sync::OnceLock;
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex, OnceLock},
+};
 
 use anyhow::{Context as _, Result};
 use axum::{
@@ -7,17 +11,25 @@ use axum::{
     routing::post,
     Json, Router, Server,
 };
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use futures::future::join_all;
+use lru::LruCache;
 use mathjax_svg::convert_to_svg;
 use resvg::usvg::{self, fontdb::Database, Tree};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tiny_skia::{Color, Pixmap, PixmapPaint, Transform};
-use usvg::{fontdb, TreeParsing, TreeTextToPath};
+use usvg::{TreeParsing, TreeTextToPath};
+
+mod fonts;
+#[cfg(test)]
+mod ssim;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let app = Router::new()
         .route("/render/svg", post(svg_handler))
-        .route("/render/png", post(png_handler));
+        .route("/render/png", post(png_handler))
+        .route("/render/batch", post(batch_handler));
     Server::bind(&ADDR.parse()?)
         .serve(app.into_make_service())
         .await?;
@@ -26,17 +38,13 @@ async fn main() -> Result<()> {
 
 /// Address to bind
 const ADDR: &str = "0.0.0.0:3000";
+/// Default number of entries kept per render-cache namespace, used when
+/// `CACHE_CAPACITY` is unset or invalid
+const DEFAULT_CACHE_CAPACITY: usize = 256;
 /// The height of the PNG
 const HEIGHT: u32 = 100;
 /// Padding size
 const PADDING: u32 = 20;
-/// Default font-family for <text> tag
-#[cfg(target_os = "macos")]
-const FONT_FAMILY: &str = "Hiragino Mincho ProN";
-#[cfg(target_os = "windows")]
-const FONT_FAMILY: &str = "Yu Mincho";
-#[cfg(not(any(target_os = "macos", target_os = "windows")))]
-const FONT_FAMILY: &str = "Noto Serif CJK JP";
 
 /// Error (to be resolved during execution)
 #[derive(thiserror::Error, Debug)]
@@ -49,19 +57,30 @@ enum Error {
     Svg(#[from] usvg::Error),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
+    #[error("invalid background color: {0}")]
+    InvalidColor(String),
+    #[error("invalid macro name: {0}")]
+    InvalidMacro(String),
+    #[error("invalid geometry: {0}")]
+    InvalidGeometry(String),
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        let msg = self.to_string();
-        if let Error::LaTeX(_) = &self {
-            let mut out = String::from("LaTeX Error: ");
-            out.push_str(&msg);
-            (StatusCode::BAD_REQUEST, out).into_response()
-        } else {
-            let mut out = String::from("Internal Error: ");
-            out.push_str(&msg);
-            (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+        match self {
+            Error::LaTeX(e) => {
+                let mut out = String::from("LaTeX Error: ");
+                out.push_str(&e.to_string());
+                (StatusCode::BAD_REQUEST, out).into_response()
+            }
+            Error::InvalidColor(_) | Error::InvalidMacro(_) | Error::InvalidGeometry(_) => {
+                (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+            }
+            other => {
+                let mut out = String::from("Internal Error: ");
+                out.push_str(&other.to_string());
+                (StatusCode::INTERNAL_SERVER_ERROR, out).into_response()
+            }
         }
     }
 }
@@ -70,6 +89,197 @@ impl IntoResponse for Error {
 #[derive(Deserialize, Debug)]
 struct Request {
     latex: String,
+    /// Target height in pixels, before `scale` is applied. Defaults to [`HEIGHT`].
+    height: Option<u32>,
+    /// Padding in pixels added around the rendered formula. Defaults to [`PADDING`].
+    padding: Option<u32>,
+    /// CSS color for the background, or `"transparent"` to skip filling it.
+    /// Only meaningful for PNG output. Defaults to opaque white.
+    background: Option<String>,
+    /// Device-pixel-ratio multiplier applied on top of `height`. Defaults to `1.0`.
+    scale: Option<f32>,
+    /// `\newcommand` macros, keyed by name without the leading backslash
+    /// (e.g. `"RR"` for `\RR`), expanded before `latex` is parsed.
+    macros: Option<HashMap<String, String>>,
+    /// Raw LaTeX prepended before `latex` (and after `macros`), for package
+    /// imports or other preamble content `macros` doesn't cover.
+    preamble: Option<String>,
+}
+
+/// A TeX control word is a backslash followed by one or more ASCII letters;
+/// `name` is expected without the leading backslash.
+fn validate_macro_name(name: &str) -> Result<(), Error> {
+    if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphabetic()) {
+        Ok(())
+    } else {
+        Err(Error::InvalidMacro(name.to_owned()))
+    }
+}
+
+/// Expand `macros` into `\newcommand` declarations, prepend `preamble`, and
+/// finally the formula itself, in the order `mathjax_svg` should see them.
+fn build_input(req: &Request) -> Result<String, Error> {
+    let mut out = String::new();
+    if let Some(macros) = &req.macros {
+        let mut names: Vec<_> = macros.keys().collect();
+        names.sort();
+        for name in names {
+            validate_macro_name(name)?;
+            out.push_str(&format!("\\newcommand{{\\{name}}}{{{}}}\n", macros[name]));
+        }
+    }
+    if let Some(preamble) = &req.preamble {
+        out.push_str(preamble);
+        out.push('\n');
+    }
+    out.push_str(req.latex.trim());
+    Ok(out)
+}
+
+/// Deterministic cache-key fragment covering `latex`, `macros` and
+/// `preamble` (`HashMap` iteration order isn't stable, so the pairs are
+/// sorted before formatting).
+fn content_key(req: &Request) -> String {
+    let mut pairs: Vec<_> = req.macros.iter().flatten().collect();
+    pairs.sort();
+    let macros = pairs
+        .iter()
+        .map(|(name, body)| format!("{name}={body}"))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{}\0{macros}\0{}",
+        req.latex.trim(),
+        req.preamble.as_deref().unwrap_or(""),
+    )
+}
+
+/// Key identifying a render result: the normalized LaTeX input together with
+/// every render parameter that affects the output
+#[derive(PartialEq, Eq, Hash, Clone)]
+struct CacheKey(String);
+
+impl CacheKey {
+    /// SVG output only depends on the formula itself (and its macros/preamble)
+    fn for_svg(req: &Request) -> Self {
+        Self(content_key(req))
+    }
+
+    /// PNG output additionally depends on geometry and background
+    fn for_png(req: &Request) -> Self {
+        Self(format!(
+            "{}\0{:?}\0{:?}\0{:?}\0{:?}",
+            content_key(req),
+            req.height,
+            req.padding,
+            req.background,
+            req.scale.map(|s| s.to_bits()),
+        ))
+    }
+}
+
+/// Render cache, shared by all requests of a given output format
+type RenderCache = Mutex<LruCache<CacheKey, Arc<Vec<u8>>>>;
+
+fn cache_capacity() -> NonZeroUsize {
+    std::env::var("CACHE_CAPACITY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .and_then(NonZeroUsize::new)
+        .unwrap_or(NonZeroUsize::new(DEFAULT_CACHE_CAPACITY).unwrap())
+}
+
+/// Cache of rendered SVG bytes, keyed on [`CacheKey`]
+static SVG_CACHE: OnceLock<RenderCache> = OnceLock::new();
+/// Cache of rendered PNG bytes, keyed on [`CacheKey`]
+static PNG_CACHE: OnceLock<RenderCache> = OnceLock::new();
+
+fn svg_cache() -> &'static RenderCache {
+    SVG_CACHE.get_or_init(|| Mutex::new(LruCache::new(cache_capacity())))
+}
+
+fn png_cache() -> &'static RenderCache {
+    PNG_CACHE.get_or_init(|| Mutex::new(LruCache::new(cache_capacity())))
+}
+
+/// Return the cached bytes for `key` in `cache`, rendering and inserting
+/// them via `render` on a miss. Shared by the single-shot and batch handlers
+/// so they hit the same cache the same way.
+fn cached_render(
+    cache: &'static RenderCache,
+    key: CacheKey,
+    render: impl FnOnce() -> Result<Vec<u8>, Error>,
+) -> Result<Arc<Vec<u8>>, Error> {
+    if let Some(hit) = cache.lock().unwrap().get(&key) {
+        return Ok(hit.clone());
+    }
+    let bytes = Arc::new(render()?);
+    cache.lock().unwrap().put(key, bytes.clone());
+    Ok(bytes)
+}
+
+/// The CSS Level 1 named colors, the ones callers are most likely to reach
+/// for instead of a hex code
+const NAMED_COLORS: &[(&str, (u8, u8, u8))] = &[
+    ("black", (0, 0, 0)),
+    ("silver", (192, 192, 192)),
+    ("gray", (128, 128, 128)),
+    ("white", (255, 255, 255)),
+    ("maroon", (128, 0, 0)),
+    ("red", (255, 0, 0)),
+    ("purple", (128, 0, 128)),
+    ("fuchsia", (255, 0, 255)),
+    ("green", (0, 128, 0)),
+    ("lime", (0, 255, 0)),
+    ("olive", (128, 128, 0)),
+    ("yellow", (255, 255, 0)),
+    ("navy", (0, 0, 128)),
+    ("blue", (0, 0, 255)),
+    ("teal", (0, 128, 128)),
+    ("aqua", (0, 255, 255)),
+];
+
+/// Parse a CSS color for use as the PNG background. `"transparent"` (any
+/// case) yields `None`, meaning the background should be left unfilled;
+/// a CSS Level 1 named color (`"white"`, `"red"`, ...) or a
+/// `#rgb`/`#rrggbb`/`#rrggbbaa` hex color is parsed otherwise.
+fn parse_background(color: &str) -> Result<Option<Color>, Error> {
+    if color.eq_ignore_ascii_case("transparent") {
+        return Ok(None);
+    }
+    if let Some((_, (r, g, b))) = NAMED_COLORS
+        .iter()
+        .find(|(name, _)| color.eq_ignore_ascii_case(name))
+    {
+        return Ok(Some(Color::from_rgba8(*r, *g, *b, 255)));
+    }
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    let channel = |s: &str| u8::from_str_radix(s, 16).ok();
+    let expand = |c: char| format!("{c}{c}");
+    let (r, g, b, a) = match hex.len() {
+        3 | 4 => {
+            let mut chars = hex.chars();
+            let r = expand(chars.next().unwrap());
+            let g = expand(chars.next().unwrap());
+            let b = expand(chars.next().unwrap());
+            let a = chars.next().map(expand).unwrap_or_else(|| "ff".to_owned());
+            (channel(&r), channel(&g), channel(&b), channel(&a))
+        }
+        6 | 8 => {
+            let a = if hex.len() == 8 { &hex[6..8] } else { "ff" };
+            (
+                channel(&hex[0..2]),
+                channel(&hex[2..4]),
+                channel(&hex[4..6]),
+                channel(a),
+            )
+        }
+        _ => (None, None, None, None),
+    };
+    match (r, g, b, a) {
+        (Some(r), Some(g), Some(b), Some(a)) => Ok(Some(Color::from_rgba8(r, g, b, a))),
+        _ => Err(Error::InvalidColor(color.to_owned())),
+    }
 }
 
 /// Create HeaderMap from content_type &'static str
@@ -84,8 +294,14 @@ macro_rules! headers_from_content_type {
 
 /// Handler to convert math to SVG
 async fn svg_handler(Json(req): Json<Request>) -> Result<impl IntoResponse, Error> {
-    let svg = convert_to_svg(req.latex)?;
-    Ok((headers_from_content_type!("image/svg+xml"), svg))
+    let key = CacheKey::for_svg(&req);
+    let svg = cached_render(svg_cache(), key, || render_svg(&req))?;
+    Ok((headers_from_content_type!("image/svg+xml"), (*svg).clone()))
+}
+
+/// Render a [`Request`] to SVG bytes
+fn render_svg(req: &Request) -> Result<Vec<u8>, Error> {
+    Ok(convert_to_svg(build_input(req)?)?.into_bytes())
 }
 
 /// Font database: only needs to be initialized once
@@ -93,7 +309,33 @@ static FONTDB: OnceLock<Database> = OnceLock::new();
 
 /// Handler to convert math to PNG
 async fn png_handler(Json(req): Json<Request>) -> Result<impl IntoResponse, Error> {
-    let svg = convert_to_svg(req.latex)?;
+    let key = CacheKey::for_png(&req);
+    let png = cached_render(png_cache(), key, || render_png(&req))?;
+    Ok((headers_from_content_type!("image/png"), (*png).clone()))
+}
+
+/// Render a [`Request`] to PNG bytes. Pulled out of [`png_handler`] so the
+/// golden-image regression tests exercise the exact same code path.
+fn render_png(req: &Request) -> Result<Vec<u8>, Error> {
+    let height = req.height.unwrap_or(HEIGHT);
+    if height == 0 {
+        return Err(Error::InvalidGeometry("height must be greater than 0".into()));
+    }
+    let padding = req.padding.unwrap_or(PADDING);
+    let scale = req.scale.unwrap_or(1.0);
+    if !(scale.is_finite() && scale > 0.0) {
+        return Err(Error::InvalidGeometry(
+            "scale must be a positive, finite number".into(),
+        ));
+    }
+    let background = req
+        .background
+        .as_deref()
+        .map(parse_background)
+        .transpose()?
+        .unwrap_or(Some(Color::WHITE));
+
+    let svg = convert_to_svg(build_input(req)?)?;
     let png = {
         let image = {
             // Convert to Pixmap
@@ -102,28 +344,26 @@ async fn png_handler(Json(req): Json<Request>) -> Result<impl IntoResponse, Erro
                 let opt = usvg::Options::default();
 
                 let mut tree = Tree::from_data(&svg_data, &opt)?;
-                tree.convert_text(FONTDB.get_or_init(|| {
-                    let mut fdb = fontdb::Database::new();
-                    fdb.load_system_fonts();
-                    // Set default serif font
-                    fdb.set_serif_family(FONT_FAMILY);
-                    fdb
-                }));
+                tree.convert_text(FONTDB.get_or_init(fonts::build_fontdb));
                 resvg::Tree::from_usvg(&tree)
             };
 
-            // Vertical length is scaled to be HEIGHT
+            // Vertical length is scaled to be HEIGHT, then the dpr scale is
+            // applied on top so high-DPI clients get crisp output
             let (mut math_pix, scale_x, scale_y) = {
                 let original_size = rtree.size;
                 let target_size = original_size
                     .to_int_size()
-                    .scale_to_height(HEIGHT)
+                    .scale_to_height(height)
                     .context("scaling Pixmap")?;
                 (
-                    tiny_skia::Pixmap::new(target_size.width(), target_size.height())
-                        .context("creating new Pixmap to draw svg in")?,
-                    target_size.width() as f32 / original_size.width(),
-                    target_size.height() as f32 / original_size.height(),
+                    tiny_skia::Pixmap::new(
+                        (target_size.width() as f32 * scale).round() as u32,
+                        (target_size.height() as f32 * scale).round() as u32,
+                    )
+                    .context("creating new Pixmap to draw svg in")?,
+                    target_size.width() as f32 / original_size.width() * scale,
+                    target_size.height() as f32 / original_size.height() * scale,
                 )
             };
             rtree.render(
@@ -134,23 +374,177 @@ async fn png_handler(Json(req): Json<Request>) -> Result<impl IntoResponse, Erro
         };
 
         let image = {
-            // Add padding and white background
-            let mut background =
-                Pixmap::new(PADDING * 2 + image.width(), PADDING * 2 + image.height())
-                    .context("creating new Pixmap for padding")?;
-            background.fill(Color::WHITE);
-            background.draw_pixmap(
-                PADDING as i32,
-                PADDING as i32,
+            // Add padding and background
+            let padding = (padding as f32 * scale).round() as u32;
+            let mut canvas = Pixmap::new(padding * 2 + image.width(), padding * 2 + image.height())
+                .context("creating new Pixmap for padding")?;
+            if let Some(color) = background {
+                canvas.fill(color);
+            }
+            canvas.draw_pixmap(
+                padding as i32,
+                padding as i32,
                 image.as_ref(),
                 &PixmapPaint::default(),
                 Transform::default(),
                 None,
             );
-            background
+            canvas
         };
 
         image.encode_png()?
     };
-    Ok((headers_from_content_type!("image/png"), png))
+    Ok(png)
+}
+
+/// Schema of a `/render/batch` request
+#[derive(Deserialize, Debug)]
+struct BatchRequest {
+    items: Vec<BatchItem>,
+}
+
+/// A single item of a batch request: the usual render [`Request`] fields,
+/// plus which format to render it as
+#[derive(Deserialize, Debug)]
+struct BatchItem {
+    #[serde(flatten)]
+    request: Request,
+    format: BatchFormat,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum BatchFormat {
+    Svg,
+    Png,
+}
+
+/// Result of rendering one [`BatchItem`]: either the payload (an SVG string,
+/// or base64-encoded PNG) or an error message, so one malformed formula
+/// doesn't fail the whole batch
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum BatchItemResponse {
+    Ok { data: String },
+    Err { error: String },
+}
+
+/// Handler for batch rendering: renders every item concurrently on blocking
+/// tasks, since rasterization is CPU-bound
+async fn batch_handler(Json(req): Json<BatchRequest>) -> Json<Vec<BatchItemResponse>> {
+    let tasks = req
+        .items
+        .into_iter()
+        .map(|item| tokio::task::spawn_blocking(move || render_batch_item(item)));
+    let results = join_all(tasks)
+        .await
+        .into_iter()
+        .map(|joined| {
+            joined.unwrap_or_else(|e| BatchItemResponse::Err {
+                error: format!("render task panicked: {e}"),
+            })
+        })
+        .collect();
+    Json(results)
+}
+
+/// Render a single [`BatchItem`], reusing the same caches as the single-shot
+/// `/render/svg` and `/render/png` routes
+fn render_batch_item(item: BatchItem) -> BatchItemResponse {
+    let result = match item.format {
+        BatchFormat::Svg => {
+            let key = CacheKey::for_svg(&item.request);
+            cached_render(svg_cache(), key, || render_svg(&item.request))
+                .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+        }
+        BatchFormat::Png => {
+            let key = CacheKey::for_png(&item.request);
+            cached_render(png_cache(), key, || render_png(&item.request))
+                .map(|bytes| BASE64.encode(&*bytes))
+        }
+    };
+    match result {
+        Ok(data) => BatchItemResponse::Ok { data },
+        Err(err) => BatchItemResponse::Err {
+            error: err.to_string(),
+        },
+    }
+}
+
+/// Golden-image regression tests for the PNG pipeline: render a fixed LaTeX
+/// corpus through [`render_png`] and compare against a committed reference
+/// PNG with [`ssim::mssim`], tolerating sub-pixel antialiasing drift across
+/// resvg/tiny-skia/mathjax-svg version bumps.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimum acceptable similarity against the committed reference image
+    const SSIM_TOLERANCE: f64 = 0.99;
+
+    /// Corpus of representative formulas: plain math and fractions/roots.
+    /// A CJK text case belongs here once a CJK font is bundled (see
+    /// `assets/fonts/README.md`) — until then it isn't portable, since the
+    /// bundled serif can't render it and golden images would only match on
+    /// machines that happen to have a CJK system font installed.
+    const CORPUS: &[(&str, &str)] = &[
+        ("e_eq_mc2", "E = mc^2"),
+        ("quadratic_formula", r"x = \frac{-b \pm \sqrt{b^2-4ac}}{2a}"),
+    ];
+
+    fn golden_path(name: &str) -> std::path::PathBuf {
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/golden")
+            .join(format!("{name}.png"))
+    }
+
+    fn decode_gray(png_bytes: &[u8]) -> ssim::GrayImage {
+        let decoder = png::Decoder::new(png_bytes);
+        let mut reader = decoder.read_info().expect("reading PNG header");
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).expect("decoding PNG frame");
+        assert_eq!(
+            info.color_type,
+            png::ColorType::Rgba,
+            "golden-image tests expect RGBA PNGs, as produced by tiny_skia::Pixmap::encode_png"
+        );
+        ssim::GrayImage::from_rgba8(info.width, info.height, &buf[..info.buffer_size()])
+    }
+
+    #[test]
+    #[ignore = "no reference PNGs are committed yet under tests/golden/; run with \
+                UPDATE_GOLDEN=1 to generate them, commit the result, then remove this \
+                #[ignore] (see tests/golden/README.md)"]
+    fn png_pipeline_matches_golden_images() {
+        for (name, latex) in CORPUS {
+            let req = Request {
+                latex: (*latex).to_owned(),
+                height: None,
+                padding: None,
+                background: None,
+                scale: None,
+                macros: None,
+                preamble: None,
+            };
+            let rendered = render_png(&req).expect("rendering should succeed");
+
+            let path = golden_path(name);
+            if std::env::var_os("UPDATE_GOLDEN").is_some() {
+                std::fs::write(&path, &rendered).expect("writing updated golden image");
+                continue;
+            }
+            let reference = std::fs::read(&path).unwrap_or_else(|_| {
+                panic!("missing golden image {path:?}; run tests with UPDATE_GOLDEN=1 to create it")
+            });
+
+            let actual = decode_gray(&rendered);
+            let expected = decode_gray(&reference);
+            let score = ssim::mssim(&expected, &actual)
+                .expect("rendered image should match the golden image's dimensions");
+            assert!(
+                score >= SSIM_TOLERANCE,
+                "{name}: SSIM {score:.4} below tolerance {SSIM_TOLERANCE}"
+            );
+        }
+    }
 }
@@ -0,0 +1,79 @@
+//! Mean Structural Similarity Index (MSSIM) between two grayscale images,
+//! used by the golden-image regression tests to tolerate sub-pixel
+//! antialiasing differences across resvg/tiny-skia/mathjax-svg upgrades.
+
+/// Grayscale image buffer, row-major luminance values in `0.0..=255.0`
+pub struct GrayImage {
+    width: u32,
+    height: u32,
+    data: Vec<f64>,
+}
+
+impl GrayImage {
+    /// Build a [`GrayImage`] from an RGBA8 buffer using Rec. 601 luma weights
+    pub fn from_rgba8(width: u32, height: u32, rgba: &[u8]) -> Self {
+        let data = rgba
+            .chunks_exact(4)
+            .map(|p| 0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64)
+            .collect();
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+
+    fn at(&self, x: u32, y: u32) -> f64 {
+        self.data[(y * self.width + x) as usize]
+    }
+}
+
+/// Side length of the sliding window SSIM is computed over
+const WINDOW: u32 = 8;
+/// Stabilizing constants from the original SSIM paper, for 8-bit pixel values
+const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+/// Mean SSIM between two grayscale images over `WINDOW`x`WINDOW` sliding
+/// windows (windows that would run past the image bounds are skipped).
+/// Returns `None` if the images differ in size.
+pub fn mssim(a: &GrayImage, b: &GrayImage) -> Option<f64> {
+    if a.width != b.width || a.height != b.height || a.width < WINDOW || a.height < WINDOW {
+        return None;
+    }
+
+    let n = (WINDOW * WINDOW) as f64;
+    let mut total = 0.0;
+    let mut windows = 0u64;
+    for wy in 0..=(a.height - WINDOW) {
+        for wx in 0..=(a.width - WINDOW) {
+            let (mut sum_a, mut sum_b) = (0.0, 0.0);
+            for y in wy..wy + WINDOW {
+                for x in wx..wx + WINDOW {
+                    sum_a += a.at(x, y);
+                    sum_b += b.at(x, y);
+                }
+            }
+            let (mu_a, mu_b) = (sum_a / n, sum_b / n);
+
+            let (mut var_a, mut var_b, mut cov) = (0.0, 0.0, 0.0);
+            for y in wy..wy + WINDOW {
+                for x in wx..wx + WINDOW {
+                    let da = a.at(x, y) - mu_a;
+                    let db = b.at(x, y) - mu_b;
+                    var_a += da * da;
+                    var_b += db * db;
+                    cov += da * db;
+                }
+            }
+            let (var_a, var_b, cov) = (var_a / n, var_b / n, cov / n);
+
+            let ssim = ((2.0 * mu_a * mu_b + C1) * (2.0 * cov + C2))
+                / ((mu_a * mu_a + mu_b * mu_b + C1) * (var_a + var_b + C2));
+            total += ssim;
+            windows += 1;
+        }
+    }
+
+    Some(total / windows as f64)
+}